@@ -0,0 +1,158 @@
+//! Loads user-overridable fan curves from `config.toml`/`config.json` in
+//! `%ProgramData%\System76\thelio-io\`, following the settings-driven approach
+//! of tools like the Fantastic fan plugin.
+
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+use serde::Deserialize;
+use thelio_io::fan::FanCurve;
+
+use crate::temp::{DEFAULT_SENSOR_ZONES, Zone};
+
+/// Looks up one of `thelio_io`'s built-in fan curves by name.
+///
+/// `thelio_io` has no points-based constructor (no `FanCurve::from_points`) to
+/// build a curve from user-supplied breakpoints, so until that lands upstream,
+/// `models`/`default_curve` can only select among the curves `driver` already
+/// ships with, by name.
+fn builtin_curve(name: &str) -> Option<FanCurve> {
+    match name {
+        "standard_smooth" => Some(FanCurve::standard_smooth()),
+        "threadripper2" => Some(FanCurve::threadripper2()),
+        "hedt" => Some(FanCurve::hedt()),
+        "xeon" => Some(FanCurve::xeon()),
+        _ => None,
+    }
+}
+
+/// Tunable gains for a zone's PID fan controller.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct PidGains {
+    /// Proportional gain.
+    pub kp: f64,
+    /// Integral gain.
+    pub ki: f64,
+    /// Derivative gain.
+    pub kd: f64,
+    /// Target temperature, in Celsius, the controller tries to hold.
+    pub setpoint: f64,
+    /// Anti-windup clamp applied to the integral accumulator.
+    #[serde(default = "default_integral_limit")]
+    pub integral_limit: f64,
+}
+
+fn default_integral_limit() -> f64 {
+    20.0
+}
+
+/// Per-zone curve names for a specific board, overriding the built-in table.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ModelCurves {
+    pub cpu: Option<String>,
+    pub intake: Option<String>,
+}
+
+/// On-disk configuration for fan curves and per-model overrides.
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    /// Maps a `"<sys_vendor> <product_version>"` board identity to per-zone
+    /// curve names, overriding `driver`'s built-in table. Each zone is
+    /// overridden independently, so a board can keep the built-in CPU curve
+    /// while overriding only Intake, or vice versa.
+    #[serde(default)]
+    models: HashMap<String, ModelCurves>,
+    /// Built-in curve name (see `builtin_curve`) used when the board matches
+    /// neither the built-in table nor `models`.
+    default_curve: Option<String>,
+    /// PID gains, keyed by zone name ("cpu" or "intake"), overriding the built-in defaults.
+    #[serde(default)]
+    pid: HashMap<String, PidGains>,
+    /// Sensor name prefix to zone name ("cpu"/"intake") mapping, overriding
+    /// `temp::DEFAULT_SENSOR_ZONES` so new sensor layouts don't need a code change.
+    #[serde(default)]
+    sensors: HashMap<String, String>,
+}
+
+impl Config {
+    /// Directory the service reads its configuration from.
+    ///
+    /// Resolves `%ProgramData%` rather than hardcoding `C:\ProgramData` so a
+    /// relocated ProgramData, or a system drive other than `C:`, still finds it.
+    pub fn dir() -> PathBuf {
+        let program_data = std::env::var("ProgramData").unwrap_or_else(|_| r"C:\ProgramData".to_string());
+        PathBuf::from(program_data).join(r"System76\thelio-io")
+    }
+
+    /// Loads `config.toml`, falling back to `config.json`, if either exists.
+    ///
+    /// Returns the default (empty) config if neither file is present, so
+    /// boards in the built-in table keep working with no configuration at all.
+    pub fn load() -> io::Result<Self> {
+        let dir = Self::dir();
+
+        if let Some(text) = read_to_string_opt(dir.join("config.toml"))? {
+            return toml::from_str(&text).map_err(to_io_err);
+        }
+
+        if let Some(text) = read_to_string_opt(dir.join("config.json"))? {
+            return serde_json::from_str(&text).map_err(to_io_err);
+        }
+
+        Ok(Config::default())
+    }
+
+    /// Looks up the curve configured for this exact board and zone ("cpu"/"intake"), if any.
+    pub fn curve_for_model(&self, sys_vendor: &str, product_version: &str, zone_name: &str) -> Option<FanCurve> {
+        let model = self.models.get(&format!("{} {}", sys_vendor, product_version))?;
+        let name = match zone_name {
+            "cpu" => model.cpu.as_ref(),
+            "intake" => model.intake.as_ref(),
+            _ => None,
+        }?;
+        builtin_curve(name)
+    }
+
+    /// Returns the safe fallback curve for boards with no built-in or
+    /// `models` entry, if the user configured one.
+    pub fn default_curve(&self) -> Option<FanCurve> {
+        builtin_curve(self.default_curve.as_ref()?)
+    }
+
+    /// Returns the user-configured PID gains for `zone_name` ("cpu"/"intake"), if any.
+    pub fn pid_gains(&self, zone_name: &str) -> Option<PidGains> {
+        self.pid.get(zone_name).copied()
+    }
+
+    /// Returns the sensor-name-prefix to fan-zone mapping: the user's `[sensors]`
+    /// section if configured, otherwise `temp::DEFAULT_SENSOR_ZONES`.
+    pub fn sensor_zones(&self) -> Vec<(String, Zone)> {
+        if self.sensors.is_empty() {
+            return DEFAULT_SENSOR_ZONES.iter()
+                .map(|(prefix, zone)| (prefix.to_string(), *zone))
+                .collect();
+        }
+
+        self.sensors.iter()
+            .filter_map(|(prefix, zone_name)| {
+                let zone = match zone_name.as_str() {
+                    "cpu" => Zone::Cpu,
+                    "intake" => Zone::Intake,
+                    _ => return None,
+                };
+                Some((prefix.clone(), zone))
+            })
+            .collect()
+    }
+}
+
+fn read_to_string_opt(path: PathBuf) -> io::Result<Option<String>> {
+    match fs::read_to_string(&path) {
+        Ok(text) => Ok(Some(text)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn to_io_err<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
@@ -1,18 +1,11 @@
+mod config;
+mod temp;
+
 use std::{
-    env::current_exe,
     ffi::OsString,
-    io::{
-        self,
-        BufRead,
-        BufReader,
-        Write,
-    },
-    process::{
-        Child,
-        Command,
-        Stdio,
-        exit,
-    },
+    io,
+    process::exit,
+    sync::mpsc,
     thread::sleep,
     time::Duration,
 };
@@ -23,6 +16,7 @@ use thelio_io::{
 use windows_service::{
     define_windows_service,
     service::{
+        PowerEventParam,
         ServiceControl,
         ServiceControlAccept,
         ServiceExitCode,
@@ -37,49 +31,175 @@ use windows_service::{
     },
 };
 
-/// Duration to wait between sensor polling requests.
+use crate::config::{Config, PidGains};
+use crate::temp::{TempSource, Zone};
+
+/// Duration to wait between sensor polling requests; also the PID controllers' `dt`.
 const POLLING_DELAY: Duration = Duration::from_secs(1);
-/// Duration to keep fans high after temperatures drop.
-const SPIN_DOWN_DELAY: Duration = Duration::from_secs(3);
-
-fn driver_loop(curve: &FanCurve, ios: &mut [Io], wrapper: &mut Child) -> io::Result<()> {
-    let mut wrapper_in = wrapper.stdin.take().unwrap();
-    let mut wrapper_out = BufReader::new(wrapper.stdout.take().unwrap());
-
-    // Build a poor-man's ring buffer that will store reported temperatures for SPIN_DOWN_DELAY.
-    // The intent is to set the fans' duty cycle based on the highest temperature in the ring
-    // buffer, rather than the most recent, preventing the fans from decreasing in speed until
-    // SPIN_DOWN_DELAY has elapsed.
-    // TODO: When `Duration::as_secs_f32()` is stabilized as a const fn, this can be made const.
-    let points_in_spin_down = (SPIN_DOWN_DELAY.as_secs_f32()/POLLING_DELAY.as_secs_f32()).ceil() as usize;
-    let mut recent_temps = vec![0.0; points_in_spin_down];
-    let mut recent_temps_i = 0; // ring buffer index
 
+/// Serial protocol device name the power-button LED's sleep state is written
+/// to, addressed the same way as the `CPUF`/`INTF` fan duty devices.
+const SLEEP_DEVICE: &str = "PWRS";
+
+/// Built-in PID gains used when `config.toml`/`config.json` has no `[pid.cpu]` entry.
+const DEFAULT_CPU_PID: PidGains = PidGains { kp: 3.0, ki: 0.15, kd: 1.0, setpoint: 65.0, integral_limit: 20.0 };
+/// Built-in PID gains used when `config.toml`/`config.json` has no `[pid.intake]` entry.
+const DEFAULT_INTAKE_PID: PidGains = PidGains { kp: 2.0, ki: 0.1, kd: 0.75, setpoint: 50.0, integral_limit: 20.0 };
+
+/// System sleep state, as reported by a `ServiceControl::PowerEvent` broadcast.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PowerState {
+    /// The system is suspending; the Io should dim/pulse the power-button LED.
+    Suspend,
+    /// The system has resumed; the Io should restore normal LED behavior.
+    Resume,
+}
+
+impl PowerState {
+    /// Maps a `PowerEventParam` to the sleep state the Io cares about, if any.
+    ///
+    /// Only the suspend/resume events are meaningful here; query events and the
+    /// battery/power-status events windows_service also reports are ignored.
+    fn from_event(param: PowerEventParam) -> Option<Self> {
+        match param {
+            PowerEventParam::Suspend => Some(PowerState::Suspend),
+            PowerEventParam::ResumeAutomatic
+            | PowerEventParam::ResumeCritical
+            | PowerEventParam::ResumeSuspend => Some(PowerState::Resume),
+            _ => None,
+        }
+    }
+}
+
+/// A proportional-integral-derivative controller tracking a target temperature.
+///
+/// Replaces the old max-over-ring-buffer spin-down hack: duty follows the
+/// temperature error continuously, and the integral accumulated while hot keeps
+/// the fans up for a while as temperature falls, so they spin down smoothly
+/// instead of stepping down abruptly once a ring buffer drains. The derivative
+/// term lowers duty while temperature is falling and raises it while rising.
+struct Pid {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    setpoint: f64,
+    /// Duty held at exactly `setpoint`, i.e. where the PID term is zero. Fixed
+    /// at construction time rather than read off the curve every step, so the
+    /// PID response doesn't compound with the curve's own rise with
+    /// temperature (that would make the controller more aggressive than its
+    /// gains call for the hotter the board already is).
+    base_duty: f64,
+    integral: f64,
+    integral_limit: f64,
+    prev_error: f64,
+    dt: f64,
+}
+
+impl Pid {
+    fn new(gains: PidGains, base_duty: u8, dt: Duration) -> Self {
+        Self {
+            kp: gains.kp,
+            ki: gains.ki,
+            kd: gains.kd,
+            setpoint: gains.setpoint,
+            base_duty: base_duty as f64,
+            integral: 0.0,
+            integral_limit: gains.integral_limit,
+            prev_error: 0.0,
+            dt: dt.as_secs_f64(),
+        }
+    }
+
+    /// Computes the absolute duty for the latest `temp` reading, in Celsius.
+    ///
+    /// The caller clamps this to `[curve.get_duty(temp), u8::MAX]`: the curve
+    /// is only ever a floor, never added on top of, so the response stays
+    /// exactly what the gains call for instead of overshooting above setpoint
+    /// or flattening to the curve below it.
+    fn step(&mut self, temp: f64) -> f64 {
+        let error = temp - self.setpoint;
+        self.integral = (self.integral + error * self.dt).clamp(-self.integral_limit, self.integral_limit);
+        let derivative = (error - self.prev_error) / self.dt;
+        self.prev_error = error;
+
+        self.base_duty + self.kp * error + self.ki * self.integral + self.kd * derivative
+    }
+}
+
+/// A fan zone's own curve, PID controller, and target device, driven
+/// independently from every other zone.
+struct ZoneState {
+    /// Serial protocol device name this zone's duty is written to (e.g. "CPUF").
+    device: &'static str,
+    zone: Zone,
+    curve: FanCurve,
+    pid: Pid,
+}
+
+impl ZoneState {
+    fn new(device: &'static str, zone: Zone, curve: FanCurve, pid_gains: PidGains) -> Self {
+        let base_duty = curve.get_duty((pid_gains.setpoint * 100.0) as i16).unwrap_or(0);
+
+        Self {
+            device,
+            zone,
+            pid: Pid::new(pid_gains, base_duty, POLLING_DELAY),
+            curve,
+        }
+    }
+}
+
+fn driver_loop(
+    zones: &mut [ZoneState],
+    ios: &mut [Io],
+    temp_source: &TempSource,
+    power_rx: &mpsc::Receiver<PowerState>,
+    stop_rx: &mpsc::Receiver<()>,
+) -> io::Result<()> {
     loop {
-        // Write a newline to the thelio-io_wrapper.exe process to unblock its `Console.ReadLine()`.
-        wrapper_in.write_all(b"\n")?;
-        let mut line = String::new();
-        wrapper_out.read_line(&mut line)?;
-
-        // This will be the highest temperature read from all available sensors.
-        // TODO: Is it possible to report individual component temperatures, or does that require
-        //       pre-motherboard configuration?
-        let read_temp = line.trim().parse::<f64>().map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                err
-            )
-        })?;
-
-        recent_temps[recent_temps_i] = read_temp;
-        recent_temps_i = (recent_temps_i + 1) % points_in_spin_down;
-
-        let temp = recent_temps.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-
-        if let Some(duty) = curve.get_duty((temp * 100.0) as i16) {
+        if stop_rx.try_recv().is_ok() {
+            break Ok(());
+        }
+
+        // Forward any pending suspend/resume notification to every Io so it can
+        // pulse/dim the power-button LED appropriately. This reuses the same
+        // generic named-device write already used for `CPUF`/`INTF` rather than
+        // requiring a new `Io` method, since the Thelio Io serial protocol
+        // addresses every write by a short device name.
+        while let Ok(power_state) = power_rx.try_recv() {
+            let sleep_duty = if power_state == PowerState::Suspend { 1 } else { 0 };
             for io in ios.iter_mut() {
-                for device in &["CPUF", "INTF"] {
-                    io.set_duty(device, duty).map_err(|err| {
+                io.set_duty(SLEEP_DEVICE, sleep_duty).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        err
+                    )
+                })?;
+            }
+        }
+
+        // Queried once per poll and shared by every zone, rather than once per
+        // zone, since every zone's reading comes off the same `Sensor` WMI class.
+        let zone_temps = temp_source.poll()?;
+
+        for zone in zones.iter_mut() {
+            // A zone with no sensor feeding it (e.g. Intake on a board with no
+            // discrete GPU) just keeps whatever duty its device was last set to.
+            let Some((_, temp)) = zone_temps.iter().find(|(z, _)| *z == zone.zone) else {
+                continue;
+            };
+            let temp = *temp;
+
+            // Keep the curve as the floor so thermals stay safe even if the PID
+            // gains are mistuned, then let the PID controller compute the actual
+            // duty so it spins down gradually as temperature falls instead of
+            // snapping back to the curve the moment the offset clamps to zero.
+            if let Some(floor_duty) = zone.curve.get_duty((temp * 100.0) as i16) {
+                let target_duty = zone.pid.step(temp);
+                let duty = target_duty.round().clamp(floor_duty as f64, u8::MAX as f64) as u8;
+
+                for io in ios.iter_mut() {
+                    io.set_duty(zone.device, duty).map_err(|err| {
                         io::Error::new(
                             io::ErrorKind::Other,
                             err
@@ -93,7 +213,7 @@ fn driver_loop(curve: &FanCurve, ios: &mut [Io], wrapper: &mut Child) -> io::Res
     }
 }
 
-fn driver() -> io::Result<()> {
+fn driver(power_rx: mpsc::Receiver<PowerState>, stop_rx: mpsc::Receiver<()>) -> io::Result<()> {
     let smbios = smbioslib::table_load_from_device()?;
 
     let sys_vendor = smbios.find_map(
@@ -104,34 +224,61 @@ fn driver() -> io::Result<()> {
         |sys: smbioslib::SMBiosSystemInformation| sys.version()
     ).unwrap_or(String::new());
 
-    let curve = match (sys_vendor.as_str(), product_version.as_str()) {
-        ("System76", "thelio-mira-r1" | "thelio-mira-r2") => {
-            log::debug!("{} {} uses the 'standard_smooth' fan curve", sys_vendor, product_version);
-            FanCurve::standard_smooth()
-        },
-        ("System76", "thelio-major-r1") => {
-            log::debug!("{} {} uses threadripper2 fan curve", sys_vendor, product_version);
-            FanCurve::threadripper2()
-        },
-        ("System76", "thelio-major-r2" | "thelio-major-r2.1" | "thelio-major-b1" | "thelio-major-b2"
-                   | "thelio-major-b3" | "thelio-mega-r1" | "thelio-mega-r1.1" ) => {
-            log::debug!("{} {} uses hedt fan curve", sys_vendor, product_version);
-            FanCurve::hedt()
-        },
-        ("System76", "thelio-massive-b1") => {
-            log::debug!("{} {} uses xeon fan curve", sys_vendor, product_version);
-            FanCurve::xeon()
-        },
-        _ => return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "unsupported sys_vendor '{}' and product_version '{}'",
-                sys_vendor,
-                product_version
-            )
-        )),
+    let config = Config::load()?;
+
+    // Resolves the curve for one zone: a per-zone `models` override if the
+    // user configured one, else the built-in per-board table, else the
+    // user's `default_curve` fallback for unsupported boards.
+    let curve_for_zone = |zone_name: &str| -> io::Result<FanCurve> {
+        if let Some(curve) = config.curve_for_model(&sys_vendor, &product_version, zone_name) {
+            log::debug!("{} {} uses a user-configured '{}' fan curve", sys_vendor, product_version, zone_name);
+            return Ok(curve);
+        }
+
+        Ok(match (sys_vendor.as_str(), product_version.as_str()) {
+            ("System76", "thelio-mira-r1" | "thelio-mira-r2") => {
+                log::debug!("{} {} uses the 'standard_smooth' fan curve", sys_vendor, product_version);
+                FanCurve::standard_smooth()
+            },
+            ("System76", "thelio-major-r1") => {
+                log::debug!("{} {} uses threadripper2 fan curve", sys_vendor, product_version);
+                FanCurve::threadripper2()
+            },
+            ("System76", "thelio-major-r2" | "thelio-major-r2.1" | "thelio-major-b1" | "thelio-major-b2"
+                       | "thelio-major-b3" | "thelio-mega-r1" | "thelio-mega-r1.1" ) => {
+                log::debug!("{} {} uses hedt fan curve", sys_vendor, product_version);
+                FanCurve::hedt()
+            },
+            ("System76", "thelio-massive-b1") => {
+                log::debug!("{} {} uses xeon fan curve", sys_vendor, product_version);
+                FanCurve::xeon()
+            },
+            _ => match config.default_curve() {
+                Some(curve) => {
+                    log::warn!(
+                        "unsupported sys_vendor '{}' and product_version '{}', using the configured default_curve for '{}'",
+                        sys_vendor,
+                        product_version,
+                        zone_name
+                    );
+                    curve
+                },
+                None => return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "unsupported sys_vendor '{}' and product_version '{}', and no default_curve configured in {}",
+                        sys_vendor,
+                        product_version,
+                        Config::dir().display()
+                    )
+                )),
+            },
+        })
     };
 
+    let cpu_curve = curve_for_zone("cpu")?;
+    let intake_curve = curve_for_zone("intake")?;
+
     let mut ios = Vec::new();
     for port_info in serialport::available_ports()? {
         match port_info.port_type {
@@ -162,19 +309,24 @@ fn driver() -> io::Result<()> {
         ));
     }
 
-    let bin_path = current_exe()?;
-    let bin_dir = bin_path.parent().unwrap();
-    let wrapper_path = bin_dir.join("thelio-io_wrapper.exe");
-    let mut wrapper = Command::new(&wrapper_path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
+    let temp_source = TempSource::new(config.sensor_zones())?;
 
-    let res = driver_loop(&curve, &mut ios, &mut wrapper);
+    let mut zones = [
+        ZoneState::new("CPUF", Zone::Cpu, cpu_curve, config.pid_gains("cpu").unwrap_or(DEFAULT_CPU_PID)),
+        ZoneState::new("INTF", Zone::Intake, intake_curve, config.pid_gains("intake").unwrap_or(DEFAULT_INTAKE_PID)),
+    ];
 
-    let _ = wrapper.kill();
+    let result = driver_loop(&mut zones, &mut ios, &temp_source, &power_rx, &stop_rx);
 
-    res
+    // Whether we're stopping cleanly or bailing out on an error, never leave the
+    // machine with fans stuck at whatever duty they last had.
+    for io in ios.iter_mut() {
+        for zone in zones.iter() {
+            let _ = io.set_duty(zone.device, u8::MAX);
+        }
+    }
+
+    result
 }
 
 fn service_main(_args: Vec<OsString>) {
@@ -182,10 +334,22 @@ fn service_main(_args: Vec<OsString>) {
     winlog::init("System76 Thelio Io").expect("failed to initialize logging");
 
     // Handle service events
-    let status_handle = service_control_handler::register("thelio-io", |event| -> ServiceControlHandlerResult {
-        //TODO: handle stop event
+    let (power_tx, power_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let status_handle = service_control_handler::register("thelio-io", move |event| -> ServiceControlHandlerResult {
         match event {
             ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            ServiceControl::PowerEvent(param) => match PowerState::from_event(param) {
+                Some(power_state) => {
+                    let _ = power_tx.send(power_state);
+                    ServiceControlHandlerResult::NoError
+                },
+                None => ServiceControlHandlerResult::NoError,
+            },
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = stop_tx.send(());
+                ServiceControlHandlerResult::NoError
+            },
             _ => ServiceControlHandlerResult::NotImplemented,
         }
     }).expect("failed to register for service events");
@@ -194,17 +358,36 @@ fn service_main(_args: Vec<OsString>) {
     status_handle.set_service_status(ServiceStatus {
         service_type: ServiceType::OWN_PROCESS,
         current_state: ServiceState::Running,
-        controls_accepted: ServiceControlAccept::empty(),
+        controls_accepted: ServiceControlAccept::POWER_EVENT | ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
         exit_code: ServiceExitCode::Win32(0),
         checkpoint: 0,
         wait_hint: Duration::default(),
         process_id: None,
     }).expect("failed to set service status");
 
-    // Run driver
-    if let Err(err) = driver() {
-        log::error!("{}\n{:#?}", err, err);
-        //TODO: set service status
+    // Run driver. Fans are always left in a safe state by `driver()` itself,
+    // whether it returns cleanly (service stop) or with an error.
+    let result = driver(power_rx, stop_rx);
+
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(err) => {
+            log::error!("{}\n{:#?}", err, err);
+            ServiceExitCode::ServiceSpecific(1)
+        },
+    };
+
+    status_handle.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: ServiceState::Stopped,
+        controls_accepted: ServiceControlAccept::empty(),
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }).expect("failed to set service status");
+
+    if result.is_err() {
         exit(1);
     }
 }
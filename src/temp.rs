@@ -0,0 +1,103 @@
+//! Reads hardware temperatures directly from WMI, replacing the
+//! `thelio-io_wrapper.exe` subprocess and its stdin/stdout IPC.
+
+use std::io;
+
+use serde::Deserialize;
+use wmi::{COMLibrary, WMIConnection};
+
+/// A single reading from LibreHardwareMonitor/OpenHardwareMonitor's `Sensor` WMI class.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Sensor {
+    name: String,
+    value: f32,
+}
+
+/// A fan zone driven by its own temperature reading and duty cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Zone {
+    /// Driven by the CPU package/core sensors; feeds the `CPUF` duty.
+    Cpu,
+    /// Driven by the hottest GPU/system sensor; feeds the `INTF` duty.
+    Intake,
+}
+
+/// Default sensor-name-prefix to fan-zone mapping, used when `config.toml`/
+/// `config.json` has no `[sensors]` section.
+///
+/// It's matched as a prefix since LibreHardwareMonitor numbers sensors of the
+/// same kind (e.g. "GPU Core #2"). New Thelio models with different sensor
+/// layouts are supported by configuring `[sensors]` rather than editing this
+/// table.
+pub const DEFAULT_SENSOR_ZONES: &[(&str, Zone)] = &[
+    ("CPU Package", Zone::Cpu),
+    ("CPU Core", Zone::Cpu),
+    ("GPU Core", Zone::Intake),
+    ("GPU Hot Spot", Zone::Intake),
+    ("Motherboard", Zone::Intake),
+    ("Temperature", Zone::Intake),
+];
+
+/// An open connection to the local hardware-monitoring WMI namespace.
+pub struct TempSource {
+    wmi_con: WMIConnection,
+    /// Sensor name prefix to fan-zone mapping, from `Config::sensor_zones()`.
+    sensor_zones: Vec<(String, Zone)>,
+}
+
+impl TempSource {
+    /// Connects to LibreHardwareMonitor's WMI namespace (`ROOT\LibreHardwareMonitor`).
+    ///
+    /// This is a hard dependency: LibreHardwareMonitor must be installed and
+    /// running as its `Sensor` class is the only WMI provider that exposes
+    /// named, per-component temperatures. OpenHardwareMonitor's
+    /// `ROOT\OpenHardwareMonitor` namespace is not tried. There is no
+    /// fallback namespace, since `MSAcpi_ThermalZoneTemperature` only reports a
+    /// single firmware thermal zone and can't be mapped to a `Zone`.
+    pub fn new(sensor_zones: Vec<(String, Zone)>) -> io::Result<Self> {
+        let com_con = COMLibrary::new().map_err(to_io_err)?;
+
+        let wmi_con = WMIConnection::with_namespace_path("ROOT\\LibreHardwareMonitor", com_con)
+            .map_err(to_io_err)?;
+
+        Ok(Self { wmi_con, sensor_zones })
+    }
+
+    fn zone_for_sensor(&self, name: &str) -> Option<Zone> {
+        self.sensor_zones.iter()
+            .find(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .map(|(_, zone)| *zone)
+    }
+
+    /// Returns the highest temperature, in Celsius, reported for each zone
+    /// this poll, in a single WMI round-trip shared by every zone. A zone
+    /// with no entry has no sensor feeding it on this board (e.g. no discrete
+    /// GPU for the Intake zone); that's not an error, the caller should just
+    /// skip driving that zone's fan for this poll.
+    pub fn poll(&self) -> io::Result<Vec<(Zone, f64)>> {
+        let sensors: Vec<Sensor> = self.wmi_con
+            .raw_query("SELECT Name, Value FROM Sensor WHERE SensorType = 'Temperature'")
+            .map_err(to_io_err)?;
+
+        let mut zone_temps: Vec<(Zone, f64)> = Vec::new();
+        for sensor in &sensors {
+            let Some(zone) = self.zone_for_sensor(&sensor.name) else {
+                continue;
+            };
+
+            let temp = sensor.value as f64;
+            match zone_temps.iter_mut().find(|(z, _)| *z == zone) {
+                Some((_, max_temp)) if *max_temp < temp => *max_temp = temp,
+                Some(_) => {}
+                None => zone_temps.push((zone, temp)),
+            }
+        }
+
+        Ok(zone_temps)
+    }
+}
+
+fn to_io_err<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}